@@ -1,16 +1,88 @@
 use warp::Filter;
+use sqlx::postgres::PgPoolOptions;
 
 #[tokio::main]
 async fn main() {
-    let db = models::blank_db();
+    logging::init();
 
-    let routes = filters::init().or(filters::todos(db)).recover(handlers::rejection);
+    let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+    let pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect(&db_url)
+        .await
+        .expect("failed to connect to Postgres");
+
+    let db = models::DbConn::new(pool);
+
+    let routes = filters::init()
+        .or(filters::todos(db))
+        .recover(handlers::rejection)
+        .with(warp::log::custom(logging::log_request))
+        .with(warp::trace::request());
+
+    // Compression can be switched off (e.g. in tests) via DISABLE_COMPRESSION.
+    let routes = if std::env::var("DISABLE_COMPRESSION").is_ok() {
+        routes.boxed()
+    } else {
+        routes.with(warp::compression::gzip()).boxed()
+    };
 
     warp::serve(routes)
         .run(([127, 0, 0, 1], 3030))
         .await;
 }
 
+mod logging {
+    use tracing_subscriber::EnvFilter;
+
+    /// Reads verbosity from `RUST_LOG` (defaulting to `info`) and installs it
+    /// as the global tracing subscriber.
+    pub fn init() {
+        tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+            .init();
+    }
+
+    /// A `warp::log::custom` callback that records method, path, status,
+    /// elapsed time, and the host/user-agent headers for every request.
+    pub fn log_request(info: warp::log::Info) {
+        let host = info
+            .request_headers()
+            .get("host")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-");
+
+        tracing::info!(
+            method = %info.method(),
+            path = info.path(),
+            status = info.status().as_u16(),
+            elapsed_ms = info.elapsed().as_millis() as u64,
+            host,
+            user_agent = info.user_agent().unwrap_or("-"),
+            "request handled"
+        );
+    }
+}
+
+mod errors {
+    use warp::reject::Reject;
+
+    #[derive(Debug)]
+    pub enum WebError {
+        DatabaseError(sqlx::Error),
+        InvalidAuthToken,
+    }
+
+    impl Reject for WebError {}
+
+    impl From<sqlx::Error> for WebError {
+        fn from(err: sqlx::Error) -> Self {
+            WebError::DatabaseError(err)
+        }
+    }
+}
+
 mod filters {
     use super::handlers;
     use warp::{Reply, Filter, Rejection};
@@ -60,27 +132,38 @@ mod filters {
         warp::path("register").and(warp::post()).and(json_body::<Employee>()).and_then(handlers::register)
     }
 
-    /// The 4 TODOs filters combined.
+    /// The 5 TODOs filters combined.
     pub fn todos(
         db: DB,
     ) -> impl Filter<Extract=impl Reply, Error=Rejection> + Clone {
         todos_list(db.clone())
+            .or(todos_get(db.clone()))
             .or(todos_create(db.clone()))
             .or(todos_update(db.clone()))
             .or(todos_delete(db))
     }
 
-    /// curl "http://127.0.0.1:3030/todos?offset=3&limit=5"
+    /// curl "http://127.0.0.1:3030/todos?offset=3&limit=5&completed=true&id[0]=1&id[1]=2"
     pub fn todos_list(
         db: DB,
     ) -> impl Filter<Extract=impl Reply, Error=Rejection> + Clone {
         warp::path!("todos")
             .and(warp::get())
-            .and(warp::query::<ListOptions>())
+            .and(serde_qs::warp::query(serde_qs::Config::new(5, false)))
             .and(with_db(db))
             .and_then(handlers::list_todos)
     }
 
+    /// curl http://127.0.0.1:3030/todos/2
+    pub fn todos_get(
+        db: DB,
+    ) -> impl Filter<Extract=impl Reply, Error=Rejection> + Clone {
+        warp::path!("todos" / u64)
+            .and(warp::get())
+            .and(with_db(db))
+            .and_then(handlers::get_todo)
+    }
+
     /// curl -d '{"text":"Sean","id":2,"completed":false}' -H "Content-Type: application/json" -X POST http://127.0.0.1:3030/todos
     pub fn todos_create(
         db: DB,
@@ -103,24 +186,33 @@ mod filters {
             .and_then(handlers::update_todo)
     }
 
-    /// curl -H "Authorization: Bearer admin" -X DELETE http://127.0.0.1:3030/todos/2
+    /// curl -H "x-api-key: sometoken" -X DELETE http://127.0.0.1:3030/todos/2
     pub fn todos_delete(
         db: DB,
     ) -> impl Filter<Extract=impl Reply, Error=Rejection> + Clone {
-        // We'll make one of our endpoints admin-only to show how authentication filters are used
-        let admin_only = warp::header::exact("authorization", "Bearer admin");
-
         warp::path!("todos" / u64)
             // It is important to put the auth check _after_ the path filters.
             // If we put the auth check before, the request `PUT /todos/invalid-string`
-            // would try this filter and reject because the authorization header doesn't match,
+            // would try this filter and reject because the token is missing/invalid,
             // rather because the param is wrong for that other path.
-            .and(admin_only)
+            .and(auth(db.clone()))
             .and(warp::delete())
             .and(with_db(db))
             .and_then(handlers::delete_todo)
     }
 
+    /// Accepts either an `access_token` cookie or an `x-api-key` header,
+    /// hashes it, and resolves it to the authenticated user's id. A missing
+    /// cookie/header is a local `InvalidAuthToken`, not a filter mismatch, so
+    /// it doesn't leak into routes that happen to also reject on a missing
+    /// header (e.g. `filters::hello`'s `user-agent`).
+    fn auth(db: DB) -> impl Filter<Extract=(i64, ), Error=Rejection> + Clone {
+        warp::cookie::optional::<String>("access_token")
+            .and(warp::header::optional::<String>("x-api-key"))
+            .and(with_db(db))
+            .and_then(handlers::authenticate)
+    }
+
     fn with_db(db: DB) -> impl Filter<Extract=(DB, ), Error=std::convert::Infallible> + Clone {
         warp::any().map(move || db.clone())
     }
@@ -139,6 +231,9 @@ mod handlers {
     use std::net::SocketAddr;
     use std::time::Duration;
 
+    use sha2::{Digest, Sha256};
+
+    use super::errors::WebError;
     use super::models::{NormalReply, Employee, Seconds, ErrorMessage, DB, ListOptions, Todo};
 
     pub async fn hi() -> Result<impl Reply, Infallible> {
@@ -159,8 +254,11 @@ mod handlers {
         Ok(warp::reply::json(&employee))
     }
 
-    pub async fn sleepy(Seconds(seconds): Seconds) -> Result<impl Reply, Infallible> {
+    #[tracing::instrument(skip_all, fields(seconds = seconds.0))]
+    pub async fn sleepy(seconds: Seconds) -> Result<impl Reply, Infallible> {
+        let Seconds(seconds) = seconds;
         tokio::time::sleep(Duration::from_secs(seconds)).await;
+        tracing::info!(seconds, "finished sleeping");
         Ok(format!("I waited {} seconds!", seconds))
     }
 
@@ -181,6 +279,18 @@ mod handlers {
             // and render it however we want
             code = StatusCode::METHOD_NOT_ALLOWED;
             message = "METHOD_NOT_ALLOWED";
+        } else if let Some(WebError::InvalidAuthToken) = err.find::<WebError>() {
+            // Covers both an unrecognized token and a missing one (see
+            // filters::auth, which normalizes "no credential supplied" into
+            // this variant instead of letting MissingCookie/MissingHeader
+            // bubble up and affect unrelated routes).
+            code = StatusCode::UNAUTHORIZED;
+            message = "UNAUTHORIZED";
+        } else if let Some(WebError::DatabaseError(e)) = err.find::<WebError>() {
+            // Something went wrong talking to Postgres; log it and report a 500.
+            eprintln!("database error: {:?}", e);
+            code = StatusCode::INTERNAL_SERVER_ERROR;
+            message = "DATABASE_ERROR";
         } else {
             // We should have expected this... Just log and say its a 500
             eprintln!("unhandled rejection: {:?}", err);
@@ -196,67 +306,125 @@ mod handlers {
         Ok(warp::reply::with_status(json, code))
     }
 
-    pub async fn list_todos(opts: ListOptions, db: DB) -> Result<impl Reply, Infallible> {
-        // Just return a JSON array of todos, applying the limit and offset.
-        let todos = db.lock().await;
-        let todos: Vec<Todo> = todos
-            .clone()
-            .into_iter()
-            .skip(opts.offset.unwrap_or(0))
-            .take(opts.limit.unwrap_or(usize::MAX))
-            .collect();
+    pub async fn list_todos(opts: ListOptions, db: DB) -> Result<impl Reply, Rejection> {
+        // Build up the WHERE clause from whichever filters the caller sent.
+        let mut builder = sqlx::QueryBuilder::new("SELECT id, text, completed FROM todos WHERE 1 = 1");
+
+        if let Some(completed) = opts.completed {
+            builder.push(" AND completed = ").push_bind(completed);
+        }
+
+        if let Some(text) = &opts.text_contains {
+            builder.push(" AND text ILIKE ").push_bind(format!("%{}%", text));
+        }
+
+        if !opts.id.is_empty() {
+            let ids: Vec<i64> = opts.id.iter().map(|id| *id as i64).collect();
+            builder.push(" AND id = ANY(").push_bind(ids).push(")");
+        }
+
+        builder
+            .push(" ORDER BY id LIMIT ")
+            .push_bind(opts.limit.map(|limit| limit as i64).unwrap_or(i64::MAX))
+            .push(" OFFSET ")
+            .push_bind(opts.offset.unwrap_or(0) as i64);
+
+        let todos: Vec<Todo> = builder
+            .build_query_as()
+            .fetch_all(&db.0)
+            .await
+            .map_err(|e| warp::reject::custom(WebError::from(e)))?;
+
         Ok(warp::reply::json(&todos))
     }
 
-    pub async fn create_todo(create: Todo, db: DB) -> Result<impl Reply, Infallible> {
-        let mut vec = db.lock().await;
+    pub async fn get_todo(id: u64, db: DB) -> Result<impl Reply, Rejection> {
+        let todo: Option<Todo> =
+            sqlx::query_as("SELECT id, text, completed FROM todos WHERE id = $1")
+                .bind(id as i64)
+                .fetch_optional(&db.0)
+                .await
+                .map_err(|e| warp::reject::custom(WebError::from(e)))?;
 
-        for todo in vec.iter() {
-            if todo.id == create.id {
-                // Todo with id already exists, return `400 BadRequest`.
-                return Ok(StatusCode::BAD_REQUEST);
-            }
+        match todo {
+            Some(todo) => Ok(Box::new(warp::reply::json(&todo)) as Box<dyn Reply>),
+            None => Ok(Box::new(StatusCode::NOT_FOUND) as Box<dyn Reply>),
         }
+    }
 
-        // No existing Todo with id, so insert and return `201 Created`.
-        vec.push(create);
+    pub async fn create_todo(create: Todo, db: DB) -> Result<impl Reply, Rejection> {
+        let result = sqlx::query("INSERT INTO todos (id, text, completed) VALUES ($1, $2, $3)")
+            .bind(create.id)
+            .bind(&create.text)
+            .bind(create.completed)
+            .execute(&db.0)
+            .await;
 
-        Ok(StatusCode::CREATED)
+        match result {
+            Ok(_) => Ok(StatusCode::CREATED),
+            // Todo with id already exists, return `400 BadRequest`.
+            Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => {
+                Ok(StatusCode::BAD_REQUEST)
+            }
+            Err(e) => Err(warp::reject::custom(WebError::from(e))),
+        }
     }
 
     pub async fn update_todo(
         id: u64,
         update: Todo,
         db: DB,
-    ) -> Result<impl Reply, Infallible> {
-        let mut vec = db.lock().await;
-
-        // Look for the specified Todo...
-        for todo in vec.iter_mut() {
-            if todo.id == id {
-                *todo = update;
-                return Ok(StatusCode::OK);
-            }
+    ) -> Result<impl Reply, Rejection> {
+        let result = sqlx::query("UPDATE todos SET text = $2, completed = $3 WHERE id = $1")
+            .bind(id as i64)
+            .bind(&update.text)
+            .bind(update.completed)
+            .execute(&db.0)
+            .await
+            .map_err(|e| warp::reject::custom(WebError::from(e)))?;
+
+        // If no rows were affected, then the ID doesn't exist...
+        if result.rows_affected() == 0 {
+            Ok(StatusCode::NOT_FOUND)
+        } else {
+            Ok(StatusCode::OK)
         }
-
-        // If the for loop didn't return OK, then the ID doesn't exist...
-        Ok(StatusCode::NOT_FOUND)
     }
 
-    pub async fn delete_todo(id: u64, db: DB) -> Result<impl Reply, Infallible> {
-        let mut vec = db.lock().await;
+    /// Hashes the extracted token and resolves it against the `users` table,
+    /// yielding the authenticated user's id to downstream handlers. Neither
+    /// credential present is treated the same as an unrecognized one.
+    pub async fn authenticate(
+        cookie: Option<String>,
+        header: Option<String>,
+        db: DB,
+    ) -> Result<i64, Rejection> {
+        let token = cookie
+            .or(header)
+            .ok_or_else(|| warp::reject::custom(WebError::InvalidAuthToken))?;
 
-        let len = vec.len();
-        vec.retain(|todo| {
-            // Retain all Todos that aren't this id...
-            // In other words, remove all that *are* this id...
-            todo.id != id
-        });
+        let mut hasher = Sha256::new();
+        hasher.update(token.as_bytes());
+        let token_hash = format!("{:x}", hasher.finalize());
+
+        let user: Option<(i64,)> = sqlx::query_as("SELECT id FROM users WHERE token_hash = $1")
+            .bind(&token_hash)
+            .fetch_optional(&db.0)
+            .await
+            .map_err(|e| warp::reject::custom(WebError::from(e)))?;
 
-        // If the vec is smaller, we found and deleted a Todo!
-        let deleted = vec.len() != len;
+        user.map(|(id,)| id)
+            .ok_or_else(|| warp::reject::custom(WebError::InvalidAuthToken))
+    }
+
+    pub async fn delete_todo(id: u64, _user_id: i64, db: DB) -> Result<impl Reply, Rejection> {
+        let result = sqlx::query("DELETE FROM todos WHERE id = $1")
+            .bind(id as i64)
+            .execute(&db.0)
+            .await
+            .map_err(|e| warp::reject::custom(WebError::from(e)))?;
 
-        if deleted {
+        if result.rows_affected() > 0 {
             // respond with a `204 No Content`, which means successful,
             // yet no body expected...
             Ok(StatusCode::NO_CONTENT)
@@ -270,8 +438,7 @@ mod models {
     use serde_derive::{Deserialize, Serialize};
     use std::str::FromStr;
     use std::net::SocketAddr;
-    use std::sync::Arc;
-    use tokio::sync::Mutex;
+    use sqlx::PgPool;
 
     #[derive(Serialize)]
     pub struct NormalReply {
@@ -307,24 +474,36 @@ mod models {
         pub rate: u32,
     }
 
-    pub type DB = Arc<Mutex<Vec<Todo>>>;
+    /// A cheaply-cloneable handle to the Postgres connection pool, passed to
+    /// every handler via the `with_db` filter.
+    #[derive(Clone)]
+    pub struct DbConn(pub PgPool);
 
-    pub fn blank_db() -> DB {
-        Arc::new(Mutex::new(Vec::new()))
+    impl DbConn {
+        pub fn new(pool: PgPool) -> Self {
+            DbConn(pool)
+        }
     }
 
-    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub type DB = DbConn;
+
+    #[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
     pub struct Todo {
-        pub id: u64,
+        pub id: i64,
         pub text: String,
         pub completed: bool,
     }
 
-    // The query parameters for list_todos.
+    // The query parameters for list_todos, parsed with serde_qs so that
+    // nested/array params like `id[]=1&id[]=2` work alongside the flat ones.
     #[derive(Debug, Deserialize)]
     pub struct ListOptions {
         pub offset: Option<usize>,
         pub limit: Option<usize>,
+        pub completed: Option<bool>,
+        pub text_contains: Option<String>,
+        #[serde(default)]
+        pub id: Vec<u64>,
     }
 }
 
@@ -364,10 +543,14 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
 
-    #[tokio::test]
-    async fn test_post() {
-        let db = models::blank_db();
-        let api = filters::todos(db);
+    // `#[sqlx::test]` spins up a fresh, migrated database per test (from
+    // ./migrations) and tears it down afterwards, so these run in the normal
+    // `cargo test` suite without a hand-managed test database. `DATABASE_URL`
+    // must still point at a Postgres server on which the test databases can
+    // be created.
+    #[sqlx::test]
+    async fn test_post(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let api = filters::todos(models::DbConn::new(pool));
 
         let resp = request()
             .method("POST")
@@ -377,13 +560,19 @@ mod tests {
             .await;
 
         assert_eq!(resp.status(), StatusCode::CREATED);
+        Ok(())
     }
 
-    #[tokio::test]
-    async fn test_post_conflict() {
-        let db = models::blank_db();
-        db.lock().await.push(todo1());
-        let api = filters::todos(db);
+    #[sqlx::test]
+    async fn test_post_conflict(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        sqlx::query("INSERT INTO todos (id, text, completed) VALUES ($1, $2, $3)")
+            .bind(todo1().id)
+            .bind(&todo1().text)
+            .bind(todo1().completed)
+            .execute(&pool)
+            .await?;
+
+        let api = filters::todos(models::DbConn::new(pool));
 
         let resp = request()
             .method("POST")
@@ -393,6 +582,7 @@ mod tests {
             .await;
 
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        Ok(())
     }
 
     fn todo1() -> Todo {